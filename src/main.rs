@@ -1,6 +1,6 @@
 mod tokens;
 
-use tokens::{ToSemanticToken, TypstSemanticToken};
+use tokens::{ToSemanticModifier, ToSemanticToken, TypstSemanticModifier, TypstSemanticToken};
 
 use strum::IntoEnumIterator;
 
@@ -8,33 +8,81 @@ use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 use typst::ide::{highlight, Tag};
-use typst::syntax::{LinkedNode, SyntaxKind};
+use typst::syntax::{LinkedNode, Source, SyntaxKind};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use dashmap::DashMap;
 use ropey::Rope;
+use serde_json::Value;
+use tokio::sync::RwLock;
+
+// a document's rope and its incrementally-reparsed syntax tree, kept behind a single map entry
+// so a request can never observe one updated without the other
+struct Document {
+    rope: Rope,
+    source: Source,
+}
 
 struct Backend {
     client: Client,
-    document_map: DashMap<Url, Rope>,
+    document_map: DashMap<Url, Document>,
+    // last full token vector served for a document, keyed by the result id it was served under,
+    // so a later `semantic_tokens_full_delta` request can diff against it
+    token_cache: DashMap<Url, (String, Vec<SemanticToken>)>,
+    next_result_id: AtomicU64,
+    // client-configured `Tag` -> standard LSP token type name overrides, from
+    // `typst.semanticTokens.mapping`; a value is interned via `Box::leak` when a tag's override
+    // is new or changed and reused on subsequent refreshes, and this is also the source of truth
+    // `build_token_types` reads from when rebuilding the legend
+    tag_overrides: RwLock<HashMap<TypstSemanticToken, &'static str>>,
+    // whether the client advertised `textDocument.semanticTokens.dynamicRegistration`; only
+    // those clients can have their legend retuned after `initialize` via workspace configuration
+    supports_dynamic_semantic_tokens: AtomicBool,
+}
+
+impl Backend {
+    fn next_result_id(&self) -> String {
+        self.next_result_id.fetch_add(1, Ordering::SeqCst).to_string()
+    }
 }
 
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
-    async fn initialize(&self, _params: InitializeParams) -> Result<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
         let text_document_sync = Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL));
 
-        let semantic_tokens_provider = Some(
-            SemanticTokensServerCapabilities::SemanticTokensOptions(SemanticTokensOptions {
-                legend: SemanticTokensLegend {
-                    token_types: TypstSemanticToken::iter()
-                        .map(|var| SemanticTokenType::new(var.to_name()))
-                        .collect(),
-                    token_modifiers: vec![],
+        let supports_dynamic_semantic_tokens = params
+            .capabilities
+            .text_document
+            .as_ref()
+            .and_then(|td| td.semantic_tokens.as_ref())
+            .and_then(|st| st.dynamic_registration)
+            .unwrap_or(false);
+        self.supports_dynamic_semantic_tokens
+            .store(supports_dynamic_semantic_tokens, Ordering::SeqCst);
+
+        // clients that support dynamic registration get the capability (re-)registered from
+        // `refresh_semantic_tokens_mapping` once we know their tag mapping, instead of here;
+        // declaring it both statically and dynamically is invalid per the LSP registration model
+        let semantic_tokens_provider = if supports_dynamic_semantic_tokens {
+            None
+        } else {
+            Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+                SemanticTokensOptions {
+                    legend: SemanticTokensLegend {
+                        token_types: build_token_types(&HashMap::new()),
+                        token_modifiers: TypstSemanticModifier::iter()
+                            .map(|var| SemanticTokenModifier::new(var.to_name()))
+                            .collect(),
+                    },
+                    full: Some(SemanticTokensFullOptions::Delta { delta: Some(true) }),
+                    range: Some(true),
+                    ..Default::default()
                 },
-                full: Some(SemanticTokensFullOptions::Bool(true)),
-                ..Default::default()
-            }),
-        );
+            ))
+        };
 
         let capabilities = ServerCapabilities {
             text_document_sync,
@@ -52,6 +100,12 @@ impl LanguageServer for Backend {
         self.client
             .log_message(MessageType::INFO, "server initialized!")
             .await;
+
+        self.refresh_semantic_tokens_mapping().await;
+    }
+
+    async fn did_change_configuration(&self, _params: DidChangeConfigurationParams) {
+        self.refresh_semantic_tokens_mapping().await;
     }
 
     async fn shutdown(&self) -> Result<()> {
@@ -64,15 +118,83 @@ impl LanguageServer for Backend {
     ) -> Result<Option<SemanticTokensResult>> {
         let uri = params.text_document.uri;
         // shouldn't be able to ask for the tokens of a document without opening it
-        let text = self.document_map.get(&uri).unwrap();
-        let source = typst::syntax::parse(&text.chunks().collect::<String>());
-        let root = LinkedNode::new(&source);
+        let doc = self.document_map.get(&uri).unwrap();
+        let root = LinkedNode::new(doc.source.root());
 
         let mut data = Vec::new();
 
         traverse_highlight(root, &mut data);
 
+        let result_id = self.next_result_id();
+        self.token_cache
+            .insert(uri, (result_id.clone(), data.clone()));
+
         Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
+            result_id: Some(result_id),
+            data,
+        })))
+    }
+
+    async fn semantic_tokens_full_delta(
+        &self,
+        params: SemanticTokensDeltaParams,
+    ) -> Result<Option<SemanticTokensFullDeltaResult>> {
+        let uri = params.text_document.uri;
+        // shouldn't be able to ask for the tokens of a document without opening it
+        let doc = self.document_map.get(&uri).unwrap();
+        let root = LinkedNode::new(doc.source.root());
+
+        let mut data = Vec::new();
+
+        traverse_highlight(root, &mut data);
+
+        let previous = self
+            .token_cache
+            .get(&uri)
+            // `DashMap::get` returns a `Ref`, not a tuple, so match ergonomics can't destructure
+            // it in the closure pattern; index into the dereffed tuple instead
+            .filter(|entry| Some(entry.0.as_str()) == params.previous_result_id.as_deref())
+            .map(|entry| entry.1.clone());
+
+        let result_id = self.next_result_id();
+        self.token_cache
+            .insert(uri, (result_id.clone(), data.clone()));
+
+        let result = match previous {
+            Some(previous) => SemanticTokensFullDeltaResult::TokensDelta(SemanticTokensDelta {
+                result_id: Some(result_id),
+                edits: diff_tokens(&previous, &data),
+            }),
+            // client's previous result id is stale or unknown, fall back to a full response
+            None => SemanticTokensFullDeltaResult::Tokens(SemanticTokens {
+                result_id: Some(result_id),
+                data,
+            }),
+        };
+
+        Ok(Some(result))
+    }
+
+    async fn semantic_tokens_range(
+        &self,
+        params: SemanticTokensRangeParams,
+    ) -> Result<Option<SemanticTokensRangeResult>> {
+        let uri = params.text_document.uri;
+        // shouldn't be able to ask for the tokens of a document without opening it
+        let doc = self.document_map.get(&uri).unwrap();
+        let root = LinkedNode::new(doc.source.root());
+
+        let start = doc.rope.line_to_char(params.range.start.line as usize)
+            + params.range.start.character as usize;
+        let end = doc.rope.line_to_char(params.range.end.line as usize)
+            + params.range.end.character as usize;
+        let byte_range = doc.rope.char_to_byte(start)..doc.rope.char_to_byte(end);
+
+        let mut data = Vec::new();
+
+        traverse_highlight_range(root, &mut data, byte_range);
+
+        Ok(Some(SemanticTokensRangeResult::Tokens(SemanticTokens {
             result_id: None,
             data,
         })))
@@ -82,28 +204,242 @@ impl LanguageServer for Backend {
         self.client
             .log_message(MessageType::INFO, "file opened!")
             .await;
+        let uri = params.text_document.uri;
         let rope = ropey::Rope::from_str(&params.text_document.text);
-        self.document_map
-            .insert(params.text_document.uri, rope.clone());
+        let source = Source::detached(params.text_document.text);
+        // inserted as one entry so a request racing this notification always sees the rope and
+        // the source appear together, never one without the other
+        self.document_map.insert(
+            uri.clone(),
+            Document {
+                rope: rope.clone(),
+                source: source.clone(),
+            },
+        );
+
+        self.publish_diagnostics(uri, &source, &rope).await;
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
         let uri = params.text_document.uri;
         let changes = params.content_changes;
-        // shouldn't be able to change a document without opening it
-        let mut rope = self.document_map.get_mut(&uri).unwrap();
-
-        for change in changes {
-            if let Some(Range { start, end }) = change.range {
-                let start_idx = rope.line_to_char(start.line as usize) + start.character as usize;
-                let end_idx = rope.line_to_char(end.line as usize) + end.character as usize;
-                rope.remove(start_idx..end_idx);
-                rope.insert(start_idx, &change.text);
-            } else {
-                *rope = Rope::from_str(&change.text);
+
+        let (rope, source) = {
+            // shouldn't be able to change a document without opening it
+            let mut doc = self.document_map.get_mut(&uri).unwrap();
+
+            for change in changes {
+                if let Some(Range { start, end }) = change.range {
+                    let start_idx =
+                        doc.rope.line_to_char(start.line as usize) + start.character as usize;
+                    let end_idx =
+                        doc.rope.line_to_char(end.line as usize) + end.character as usize;
+                    let start_byte = doc.rope.char_to_byte(start_idx);
+                    let end_byte = doc.rope.char_to_byte(end_idx);
+
+                    doc.rope.remove(start_idx..end_idx);
+                    doc.rope.insert(start_idx, &change.text);
+                    // reparse only the edited span instead of the whole document
+                    doc.source.edit(start_byte..end_byte, &change.text);
+                } else {
+                    doc.rope = Rope::from_str(&change.text);
+                    doc.source = Source::detached(change.text);
+                }
+            }
+
+            (doc.rope.clone(), doc.source.clone())
+        };
+
+        self.publish_diagnostics(uri, &source, &rope).await;
+    }
+}
+
+impl Backend {
+    // requests `typst.semanticTokens.mapping` from the client, rebuilds the legend using the
+    // client's preferred standard token type names (built-in names where unset), then re-registers the
+    // semantic tokens capability so highlighting can be retuned without restarting the server
+    async fn refresh_semantic_tokens_mapping(&self) {
+        // without dynamic registration support there's no valid way to change the legend after
+        // the capability `initialize` already declared statically, so don't bother asking
+        if !self
+            .supports_dynamic_semantic_tokens
+            .load(Ordering::SeqCst)
+        {
+            return;
+        }
+
+        let items = vec![ConfigurationItem {
+            scope_uri: None,
+            section: Some("typst.semanticTokens.mapping".to_string()),
+        }];
+
+        // an unset mapping (or a failed request) falls back to the built-in names: the capability
+        // is still registered, and any previously configured overrides are dropped below
+        let mapping = match self.client.configuration(items).await.ok().and_then(|mut values| values.pop()) {
+            Some(Value::Object(mapping)) => mapping,
+            _ => serde_json::Map::new(),
+        };
+
+        let mut desired = HashMap::new();
+        for (tag_name, mapped_name) in mapping {
+            let Value::String(mapped_name) = mapped_name else {
+                continue;
+            };
+            if let Some(tag) =
+                TypstSemanticToken::iter().find(|tag| tag.to_name().eq_ignore_ascii_case(&tag_name))
+            {
+                desired.insert(tag, mapped_name);
             }
         }
+
+        let legend = {
+            let mut overrides = self.tag_overrides.write().await;
+
+            // drop tags that are no longer overridden, or whose override value changed (a
+            // changed value is re-interned below, under the same key)
+            overrides.retain(|tag, name| desired.get(tag).is_some_and(|d| d.as_str() == *name));
+
+            // intern each value once; repeat refreshes that send the same mapping reuse the
+            // already-leaked string instead of leaking a fresh one per call
+            for (tag, name) in desired {
+                overrides
+                    .entry(tag)
+                    .or_insert_with(|| Box::leak(name.into_boxed_str()) as &'static str);
+            }
+
+            SemanticTokensLegend {
+                token_types: build_token_types(&overrides),
+                token_modifiers: TypstSemanticModifier::iter()
+                    .map(|var| SemanticTokenModifier::new(var.to_name()))
+                    .collect(),
+            }
+        };
+
+        let registration = Registration {
+            id: "typst-semantic-tokens".to_string(),
+            method: "textDocument/semanticTokens".to_string(),
+            register_options: serde_json::to_value(SemanticTokensRegistrationOptions {
+                text_document_registration_options: TextDocumentRegistrationOptions {
+                    document_selector: None,
+                },
+                semantic_tokens_options: SemanticTokensOptions {
+                    legend,
+                    full: Some(SemanticTokensFullOptions::Delta { delta: Some(true) }),
+                    range: Some(true),
+                    ..Default::default()
+                },
+                static_registration_options: StaticRegistrationOptions::default(),
+            })
+            .ok(),
+        };
+
+        // drop whatever we previously registered under this id first: registering the same id
+        // twice without unregistering is invalid per the LSP registration model
+        let _ = self
+            .client
+            .unregister_capability(vec![Unregistration {
+                id: "typst-semantic-tokens".to_string(),
+                method: "textDocument/semanticTokens".to_string(),
+            }])
+            .await;
+
+        // best-effort: clients that stop supporting dynamic registration mid-session would have
+        // already been filtered out above
+        let _ = self.client.register_capability(vec![registration]).await;
+    }
+
+    async fn publish_diagnostics(&self, uri: Url, source: &Source, rope: &Rope) {
+        let root = LinkedNode::new(source.root());
+
+        let mut diagnostics = Vec::new();
+        collect_error_diagnostics(&root, rope, &mut diagnostics);
+
+        self.client.publish_diagnostics(uri, diagnostics, None).await;
+    }
+}
+
+// walks the tree collecting every node that highlights as an error, or whose `SyntaxKind` Typst
+// itself marks as erroneous (unclosed delimiters, malformed headings, etc.)
+fn collect_error_diagnostics(node: &LinkedNode, rope: &Rope, diagnostics: &mut Vec<Diagnostic>) {
+    let is_error =
+        matches!(highlight(node).into(), TypstSemanticToken::Error) || node.kind().is_error();
+
+    if is_error {
+        // nodes Typst itself marked as erroneous carry the parser's own descriptive message;
+        // fall back to the bare kind only for nodes we flagged via the `Error` highlight tag
+        let message = node
+            .message()
+            .map(|message| message.to_string())
+            .unwrap_or_else(|| format!("unexpected {:?}", node.kind()));
+
+        diagnostics.push(Diagnostic {
+            range: byte_range_to_lsp_range(rope, node.range()),
+            severity: Some(DiagnosticSeverity::ERROR),
+            source: Some("typst".to_string()),
+            message,
+            ..Default::default()
+        });
+    }
+
+    for child in node.children() {
+        collect_error_diagnostics(&child, rope, diagnostics);
+    }
+}
+
+// shared byte-offset -> LSP `Position`/`Range` conversion; the semantic-token path needs the
+// inverse (`Position` -> char/byte offset) when resolving `semantic_tokens_range` requests
+fn byte_to_lsp_position(rope: &Rope, byte_offset: usize) -> Position {
+    let char_idx = rope.byte_to_char(byte_offset);
+    let line = rope.char_to_line(char_idx);
+    let character = (char_idx - rope.line_to_char(line)) as u32;
+    Position::new(line as u32, character)
+}
+
+fn byte_range_to_lsp_range(rope: &Rope, range: std::ops::Range<usize>) -> Range {
+    Range {
+        start: byte_to_lsp_position(rope, range.start),
+        end: byte_to_lsp_position(rope, range.end),
+    }
+}
+
+// builds the legend's token type names, substituting in any client-configured overrides; callers
+// are expected to have already interned override values (see `refresh_semantic_tokens_mapping`)
+// rather than leaking a fresh string on every call
+fn build_token_types(overrides: &HashMap<TypstSemanticToken, &'static str>) -> Vec<SemanticTokenType> {
+    TypstSemanticToken::iter()
+        .map(|tag| match overrides.get(&tag) {
+            Some(name) => SemanticTokenType::new(*name),
+            None => SemanticTokenType::new(tag.to_name()),
+        })
+        .collect()
+}
+
+// finds the common prefix/suffix between the previously served and freshly computed tokens and
+// encodes everything in between as a single edit, as the LSP delta format expects
+fn diff_tokens(old: &[SemanticToken], new: &[SemanticToken]) -> Vec<SemanticTokensEdit> {
+    let prefix_len = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+
+    let max_suffix_len = old.len().min(new.len()) - prefix_len;
+    let suffix_len = old[prefix_len..]
+        .iter()
+        .rev()
+        .zip(new[prefix_len..].iter().rev())
+        .take(max_suffix_len)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    if old.len() == new.len() && prefix_len + suffix_len == old.len() {
+        return Vec::new();
     }
+
+    let delete_count = (old.len() - prefix_len - suffix_len) as u32;
+    let data = new[prefix_len..new.len() - suffix_len].to_vec();
+
+    vec![SemanticTokensEdit {
+        start: (prefix_len * 5) as u32,
+        delete_count: delete_count * 5,
+        data: Some(data),
+    }]
 }
 
 struct HighlightFeedForward {
@@ -119,14 +455,58 @@ fn traverse_highlight(node: LinkedNode, tokens: &mut Vec<SemanticToken>) {
             delta_line: 0,
             delta_start: 0,
         },
+        None,
+        0,
+    );
+}
+
+// only emits tokens overlapping `range` (byte offsets), but still walks every node before the
+// range so the feed-forward bookkeeping stays correct for the first token inside the range;
+// nodes starting at or after the range's end are not visited
+fn traverse_highlight_range(
+    node: LinkedNode,
+    tokens: &mut Vec<SemanticToken>,
+    range: std::ops::Range<usize>,
+) {
+    traverse_highlight_rec(
+        node,
+        tokens,
+        HighlightFeedForward {
+            delta_line: 0,
+            delta_start: 0,
+        },
+        Some(&range),
+        0,
     );
 }
 
+// modifier bits contributed by being inside this node, layered onto whatever the ancestors
+// already contributed
+fn context_modifier_bits(kind: SyntaxKind) -> u32 {
+    match kind {
+        SyntaxKind::Math => TypstSemanticModifier::Math.to_bit(),
+        SyntaxKind::Code => TypstSemanticModifier::Code.to_bit(),
+        SyntaxKind::Markup => TypstSemanticModifier::Markup.to_bit(),
+        _ => 0,
+    }
+}
+
 fn traverse_highlight_rec(
     node: LinkedNode,
     tokens: &mut Vec<SemanticToken>,
     mut ff: HighlightFeedForward,
+    range: Option<&std::ops::Range<usize>>,
+    modifiers: u32,
 ) -> HighlightFeedForward {
+    // once we're past the end of the requested range there's nothing left worth visiting
+    if let Some(range) = range {
+        if node.range().start >= range.end {
+            return ff;
+        }
+    }
+
+    let modifiers = modifiers | context_modifier_bits(node.kind());
+
     let children = node.children();
 
     let len = children.len();
@@ -137,7 +517,7 @@ fn traverse_highlight_rec(
         TypstSemanticToken::Emph | TypstSemanticToken::Strong
     ) {
         for child in children {
-            ff = traverse_highlight_rec(child, tokens, ff);
+            ff = traverse_highlight_rec(child, tokens, ff, range, modifiers);
         }
         if len > 0 {
             return ff;
@@ -153,7 +533,20 @@ fn traverse_highlight_rec(
     let highlight_type = highlight(&node).into();
     let node_len = node.range().len() as u32;
 
-    let (skip_line, skip_start) = if matches!(highlight_type, TypstSemanticToken::None) {
+    let modifiers = modifiers
+        | match highlight_type {
+            TypstSemanticToken::Strong => TypstSemanticModifier::Strong.to_bit(),
+            TypstSemanticToken::Emph => TypstSemanticModifier::Emph.to_bit(),
+            _ => 0,
+        };
+
+    let in_range = range.map_or(true, |range| {
+        let node_range = node.range();
+        node_range.end > range.start && node_range.start < range.end
+    });
+
+    let (skip_line, skip_start) = if matches!(highlight_type, TypstSemanticToken::None) || !in_range
+    {
         (delta_line, delta_start + node_len)
     } else {
         tokens.push(SemanticToken {
@@ -161,7 +554,7 @@ fn traverse_highlight_rec(
             delta_start,
             length: node_len,
             token_type: highlight_type.to_idx(),
-            token_modifiers_bitset: 0,
+            token_modifiers_bitset: modifiers,
         });
         (0, node_len)
     };
@@ -180,21 +573,27 @@ fn traverse_highlight_rec(
         }
     } else if matches!(node.kind(), SyntaxKind::Raw) && node.text().contains("\n") {
         // this is a multiline raw block
-        // mark each included line as raw
+        // mark each included line as raw if the raw node overlaps `range` (all lines or none),
+        // consistent with the single-line case above
         let mut last_len = 0;
+        let mut line_count = 0;
         // skip the first because we've already done it
         for line in node.text().split("\n").skip(1) {
-            tokens.push(SemanticToken {
-                delta_line: 1,
-                delta_start: 0,
-                length: line.len() as u32,
-                token_type: Tag::Raw.to_idx(),
-                token_modifiers_bitset: 0,
-            });
+            if in_range {
+                tokens.push(SemanticToken {
+                    delta_line: 1,
+                    delta_start: 0,
+                    length: line.len() as u32,
+                    token_type: Tag::Raw.to_idx(),
+                    token_modifiers_bitset: modifiers,
+                });
+            }
             last_len = line.len() as u32;
+            line_count += 1;
         }
         HighlightFeedForward {
-            delta_line: 0,
+            // emitted lines carry their own line breaks; skipped ones are carried forward
+            delta_line: if in_range { 0 } else { skip_line + line_count },
             delta_start: last_len,
         }
     } else {
@@ -213,6 +612,10 @@ async fn main() {
     let (service, socket) = LspService::new(|client| Backend {
         client,
         document_map: DashMap::new(),
+        token_cache: DashMap::new(),
+        next_result_id: AtomicU64::new(0),
+        tag_overrides: RwLock::new(HashMap::new()),
+        supports_dynamic_semantic_tokens: AtomicBool::new(false),
     });
     Server::new(stdin, stdout, socket).serve(service).await;
 }
@@ -75,6 +75,32 @@ impl From<Option<Tag>> for TypstSemanticToken {
     }
 }
 
+/// Layered on top of the base token type, mirroring how mature LSPs let a theme color e.g. a
+/// `Number` inside math differently from a `Number` in code without inventing new token types.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, EnumIter, IntoStaticStr)]
+#[repr(u32)]
+pub enum TypstSemanticModifier {
+    Math,
+    Code,
+    Markup,
+    Strong,
+    Emph,
+}
+
+pub trait ToSemanticModifier {
+    fn to_name(&self) -> &'static str;
+    fn to_bit(&self) -> u32;
+}
+
+impl ToSemanticModifier for TypstSemanticModifier {
+    fn to_name(&self) -> &'static str {
+        self.into()
+    }
+    fn to_bit(&self) -> u32 {
+        1 << (*self as u32)
+    }
+}
+
 impl From<Tag> for TypstSemanticToken {
     fn from(value: Tag) -> Self {
         use Tag::*;